@@ -5,7 +5,7 @@ extern crate strum_macros;
 
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::fmt::Debug;
 use std::sync::{Arc, Weak};
@@ -38,10 +38,142 @@ enum Atom {
  *  (... |callbacks|)
  */
 
+/* A Magma/Monoid over `Atom`: `operate` is the associative combine and
+ * `unit` is its identity element for a given `typ` (needed because `Atom`
+ * is multi-variant, so "zero" means different things for different
+ * payloads). Implementations are expected to panic on mismatched variants,
+ * the same way an adapter `NodeState` panics when handed the wrong `Atom`
+ * (see `EntityToUsizeState`).
+ */
+trait Combine: Debug {
+    fn operate(&self, a: &Atom, b: &Atom) -> Atom;
+    fn unit(&self, typ: AtomDiscriminants) -> Atom;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SumCombine;
+
+impl Combine for SumCombine {
+    fn operate(&self, a: &Atom, b: &Atom) -> Atom {
+        match (a, b) {
+            (Atom::Entity(x), Atom::Entity(y)) => Atom::Entity(x.wrapping_add(*y)),
+            #[cfg(test)]
+            (Atom::TestUsize(x), Atom::TestUsize(y)) => Atom::TestUsize(x + y),
+            _ => panic!("SumCombine cannot combine {:?} with {:?}", a, b),
+        }
+    }
+    fn unit(&self, typ: AtomDiscriminants) -> Atom {
+        match typ {
+            AtomDiscriminants::Entity => Atom::Entity(0),
+            #[cfg(test)]
+            AtomDiscriminants::TestUsize => Atom::TestUsize(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MaxCombine;
+
+impl Combine for MaxCombine {
+    fn operate(&self, a: &Atom, b: &Atom) -> Atom {
+        match (a, b) {
+            (Atom::Entity(x), Atom::Entity(y)) => Atom::Entity(*x.max(y)),
+            #[cfg(test)]
+            (Atom::TestUsize(x), Atom::TestUsize(y)) => Atom::TestUsize(*x.max(y)),
+            _ => panic!("MaxCombine cannot combine {:?} with {:?}", a, b),
+        }
+    }
+    fn unit(&self, typ: AtomDiscriminants) -> Atom {
+        match typ {
+            AtomDiscriminants::Entity => Atom::Entity(u8::MIN),
+            #[cfg(test)]
+            AtomDiscriminants::TestUsize => Atom::TestUsize(usize::MIN),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MinCombine;
+
+impl Combine for MinCombine {
+    fn operate(&self, a: &Atom, b: &Atom) -> Atom {
+        match (a, b) {
+            (Atom::Entity(x), Atom::Entity(y)) => Atom::Entity(*x.min(y)),
+            #[cfg(test)]
+            (Atom::TestUsize(x), Atom::TestUsize(y)) => Atom::TestUsize(*x.min(y)),
+            _ => panic!("MinCombine cannot combine {:?} with {:?}", a, b),
+        }
+    }
+    fn unit(&self, typ: AtomDiscriminants) -> Atom {
+        match typ {
+            AtomDiscriminants::Entity => Atom::Entity(u8::MAX),
+            #[cfg(test)]
+            AtomDiscriminants::TestUsize => Atom::TestUsize(usize::MAX),
+        }
+    }
+}
+
+// Logical-or over a payload treated as a bitmask (so a set of boolean-ish
+// flags packed into the same integer all survive the fold independently).
+#[derive(Debug, Clone, Copy)]
+struct OrCombine;
+
+impl Combine for OrCombine {
+    fn operate(&self, a: &Atom, b: &Atom) -> Atom {
+        match (a, b) {
+            (Atom::Entity(x), Atom::Entity(y)) => Atom::Entity(x | y),
+            #[cfg(test)]
+            (Atom::TestUsize(x), Atom::TestUsize(y)) => Atom::TestUsize(x | y),
+            _ => panic!("OrCombine cannot combine {:?} with {:?}", a, b),
+        }
+    }
+    fn unit(&self, typ: AtomDiscriminants) -> Atom {
+        match typ {
+            AtomDiscriminants::Entity => Atom::Entity(0),
+            #[cfg(test)]
+            AtomDiscriminants::TestUsize => Atom::TestUsize(0),
+        }
+    }
+}
+
+/* In "reduce" mode a `Link` no longer has a single source: it holds one
+ * slot per source index, and every `update` recomputes the fold
+ * `op(unit(), op(s0, op(s1, ...)))` over whichever slots have been filled
+ * so far before forwarding the folded value to sinks. Recomputing from
+ * `unit()` each time (rather than trying to update the fold incrementally)
+ * keeps this correct even for combiners that aren't invertible, like an
+ * element-wise max.
+ */
+enum LinkMode {
+    LastWriterWins,
+    Reduce {
+        combiner: Arc<dyn Combine>,
+        slots: Vec<Option<Atom>>,
+    },
+}
+
+// What a `Link` forwards an update to: either a real node input, or another
+// `Link`'s reduce slot (so one producer's output can feed into a fan-in
+// aggregation rather than only into a node; see `attach_combined`).
+enum LinkSink {
+    Input(InputParameter),
+    Reduce(Arc<RefCell<Link>>, usize),
+}
+
+impl LinkSink {
+    fn mark_changed(&self, value: Atom) {
+        match self {
+            LinkSink::Input(input) => input.mark_changed(value),
+            LinkSink::Reduce(link, source_idx) => link.borrow_mut().update(*source_idx, value),
+        }
+    }
+}
+
 struct Link {
     typ: AtomDiscriminants,
     latest_value: Option<Atom>,
-    sinks: Vec<InputParameter>,
+    sinks: Vec<LinkSink>,
+    mode: LinkMode,
 }
 
 impl Link {
@@ -50,27 +182,131 @@ impl Link {
             typ,
             latest_value: None,
             sinks: vec![],
+            mode: LinkMode::LastWriterWins,
         }
     }
-    fn update(&mut self, next: Atom) {
+
+    // `num_sources` is the number of distinct `source_idx`s `update` will be
+    // called with; each gets its own slot in the fold.
+    fn new_reduce(typ: AtomDiscriminants, num_sources: usize, combiner: Arc<dyn Combine>) -> Self {
+        Self {
+            typ,
+            latest_value: None,
+            sinks: vec![],
+            mode: LinkMode::Reduce {
+                combiner,
+                slots: vec![None; num_sources],
+            },
+        }
+    }
+
+    fn update(&mut self, source_idx: usize, next: Atom) {
         assert_eq!(self.typ, next.into());
-        self.latest_value = Some(next);
-        for sink in self.sinks.iter_mut() {
-            sink.mark_changed(next);
+        let typ = self.typ;
+        let forwarded = match &mut self.mode {
+            LinkMode::LastWriterWins => next,
+            LinkMode::Reduce { combiner, slots } => {
+                slots[source_idx] = Some(next);
+                let mut folded = combiner.unit(typ);
+                for slot in slots.iter().flatten() {
+                    folded = combiner.operate(&folded, slot);
+                }
+                folded
+            }
+        };
+        self.latest_value = Some(forwarded);
+        for sink in self.sinks.iter() {
+            sink.mark_changed(forwarded);
         }
     }
     fn get_latest(&self) -> Option<Atom> {
         self.latest_value
     }
     fn add_sink(&mut self, sink: &InputParameter) {
-        self.sinks.push(sink.clone());
+        self.sinks.push(LinkSink::Input(sink.clone()));
+    }
+    fn add_reduce_sink(&mut self, reduce_link: &Arc<RefCell<Link>>, source_idx: usize) {
+        self.sinks.push(LinkSink::Reduce(reduce_link.clone(), source_idx));
+    }
+}
+
+/* A template's inputs/outputs are no longer required to name a concrete
+ * `AtomDiscriminants` up front: `AtomType::Var` stands in for "whatever type
+ * the synthesizer manages to unify this slot with", which is how a generic
+ * combinator like an identity node (`T -> T`) gets expressed. `generate_graphs`
+ * resolves these variables against a `Substitution` as it searches; by the
+ * time a template is actually instantiated every variable must have been
+ * bound to a concrete type.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum AtomType {
+    Concrete(AtomDiscriminants),
+    Var(u32),
+}
+
+impl From<AtomDiscriminants> for AtomType {
+    fn from(discriminant: AtomDiscriminants) -> Self {
+        AtomType::Concrete(discriminant)
+    }
+}
+
+/* Standard Hindley-Milner style unification: `subst` maps a type variable to
+ * whatever it's currently bound to (possibly another variable). `resolve`
+ * follows that chain to a fixed point, and `unify` extends the substitution
+ * so that `a` and `b` describe the same type, rejecting cyclic bindings via
+ * the occurs check.
+ */
+type Substitution = HashMap<u32, AtomType>;
+
+fn resolve(typ: AtomType, subst: &Substitution) -> AtomType {
+    match typ {
+        AtomType::Var(var) => match subst.get(&var) {
+            Some(&bound) => resolve(bound, subst),
+            None => typ,
+        },
+        AtomType::Concrete(_) => typ,
+    }
+}
+
+fn occurs(var: u32, typ: AtomType, subst: &Substitution) -> bool {
+    matches!(resolve(typ, subst), AtomType::Var(other) if other == var)
+}
+
+fn unify(a: AtomType, b: AtomType, subst: &mut Substitution) -> Result<(), String> {
+    match (resolve(a, subst), resolve(b, subst)) {
+        (AtomType::Concrete(x), AtomType::Concrete(y)) => {
+            if x == y {
+                Ok(())
+            } else {
+                Err(format!("cannot unify {:?} with {:?}", x, y))
+            }
+        }
+        (AtomType::Var(v), AtomType::Var(w)) if v == w => Ok(()),
+        (AtomType::Var(v), other) | (other, AtomType::Var(v)) => {
+            if occurs(v, other, subst) {
+                Err(format!("occurs check failed: ?{} occurs in {:?}", v, other))
+            } else {
+                subst.insert(v, other);
+                Ok(())
+            }
+        }
+    }
+}
+
+/* Every variable must be bound by the time a template is instantiated into a
+ * real `Link`, which only ever carries a concrete `AtomDiscriminants`.
+ */
+fn resolve_concrete(typ: AtomType, subst: &Substitution) -> AtomDiscriminants {
+    match resolve(typ, subst) {
+        AtomType::Concrete(discriminant) => discriminant,
+        AtomType::Var(var) => panic!("type variable ?{} left unresolved at instantiation", var),
     }
 }
 
 trait NodeTemplate {
-    fn in_types(&self) -> Vec<AtomDiscriminants>;
-    fn out_types(&self) -> Vec<AtomDiscriminants>;
-    fn create(&self) -> Arc<RefCell<dyn Node>>;
+    fn in_types(&self) -> Vec<AtomType>;
+    fn out_types(&self) -> Vec<AtomType>;
+    fn create(&self, subst: &Substitution) -> Arc<RefCell<dyn Node>>;
 }
 
 impl Debug for NodeTemplate {
@@ -91,6 +327,9 @@ type OutLinkList = Vec<Arc<RefCell<Link>>>;
 trait Node {
     fn in_links(&self) -> &InLinkList;
     fn out_links(&self) -> &OutLinkList;
+    // Concrete types of `in_links`, resolved from the template's (possibly
+    // polymorphic) `in_types()` via the `Substitution` used at instantiation.
+    fn in_types(&self) -> &Vec<AtomDiscriminants>;
     fn template(&self) -> &Arc<dyn NodeTemplate>;
     fn get_callback_ref(&self, idx: usize) -> CallbackRef;
     #[cfg(test)]
@@ -105,6 +344,7 @@ trait NodeState: Default + Any + Debug {
 
 struct SimpleNode<StateT: NodeState> {
     in_links: InLinkList,
+    in_types: Vec<AtomDiscriminants>,
     template: Arc<dyn NodeTemplate>,
     state: Arc<StateT>,
     out_links: OutLinkList,
@@ -118,6 +358,9 @@ impl<T: NodeState> Node for SimpleNode<T> {
     fn out_links(&self) -> &OutLinkList {
         &self.out_links
     }
+    fn in_types(&self) -> &Vec<AtomDiscriminants> {
+        &self.in_types
+    }
     fn template(&self) -> &Arc<dyn NodeTemplate> {
         &self.template
     }
@@ -132,7 +375,10 @@ impl<T: NodeState> Node for SimpleNode<T> {
 }
 
 impl<StateT: NodeState + 'static> SimpleNode<StateT> {
-    pub fn from_template(template: Arc<dyn NodeTemplate>) -> Arc<RefCell<SimpleNode<StateT>>> {
+    pub fn from_template(
+        template: Arc<dyn NodeTemplate>,
+        subst: &Substitution,
+    ) -> Arc<RefCell<SimpleNode<StateT>>> {
         fn initialize_callback_refs<T: NodeState + 'static>(
             node_ref: &Arc<RefCell<SimpleNode<T>>>,
         ) {
@@ -155,15 +401,21 @@ impl<StateT: NodeState + 'static> SimpleNode<StateT> {
             }
         }
 
-        let in_links = template.in_types().iter().map(|_typ| None).collect();
+        let in_types: Vec<AtomDiscriminants> = template
+            .in_types()
+            .iter()
+            .map(|typ| resolve_concrete(*typ, subst))
+            .collect();
+        let in_links = in_types.iter().map(|_typ| None).collect();
         let out_links = template
             .out_types()
             .iter()
-            .map(|typ| Arc::new(RefCell::new(Link::new(*typ))))
+            .map(|typ| Arc::new(RefCell::new(Link::new(resolve_concrete(*typ, subst)))))
             .collect();
         let state = Arc::new(Default::default());
         let ret = Arc::new(RefCell::new(Self {
             in_links,
+            in_types,
             out_links,
             template: template.clone(),
             state,
@@ -174,6 +426,136 @@ impl<StateT: NodeState + 'static> SimpleNode<StateT> {
     }
 }
 
+/* A pre-wired subgraph packaged so it can be dropped into a larger graph as
+ * if it were a single node. `nodes` holds every inner node (kept alive for
+ * as long as the fragment is); `in_boundary` lists, for each exposed input,
+ * every inner (node index, input slot) it should fan out to (more than one
+ * target lets a single logical input drive several inner sinks at once);
+ * `out_boundary` names, for each exposed output, the single inner (node
+ * index, output slot) it's sourced from. An empty fragment (`nodes` empty,
+ * both boundaries empty) is a valid pure pass-through.
+ */
+struct CompositeFragment {
+    nodes: Vec<Arc<RefCell<dyn Node>>>,
+    in_boundary: Vec<Vec<(usize, usize)>>,
+    out_boundary: Vec<(usize, usize)>,
+}
+
+/* The facade `Node` a `CompositeTemplate` instantiates: its `out_links` are
+ * literally the inner boundary `Link`s (so attaching a sink to the facade
+ * attaches it to the real inner source), and its callback refs forward an
+ * incoming value to every inner sink in the corresponding `in_boundary`
+ * group.
+ */
+struct CompositeNode {
+    fragment: CompositeFragment,
+    in_types: Vec<AtomDiscriminants>,
+    in_links: InLinkList,
+    out_links: OutLinkList,
+    template: Arc<dyn NodeTemplate>,
+}
+
+impl Node for CompositeNode {
+    fn in_links(&self) -> &InLinkList {
+        &self.in_links
+    }
+    fn out_links(&self) -> &OutLinkList {
+        &self.out_links
+    }
+    fn in_types(&self) -> &Vec<AtomDiscriminants> {
+        &self.in_types
+    }
+    fn template(&self) -> &Arc<dyn NodeTemplate> {
+        &self.template
+    }
+    fn get_callback_ref(&self, idx: usize) -> CallbackRef {
+        let targets = self.fragment.in_boundary[idx].clone();
+        let nodes = self.fragment.nodes.clone();
+        Arc::new(move |atom| {
+            for (node_idx, slot_idx) in &targets {
+                nodes[*node_idx].borrow().get_callback_ref(*slot_idx)(atom);
+            }
+        })
+    }
+    #[cfg(test)]
+    fn state(&self) -> Arc<dyn Any> {
+        Arc::new(())
+    }
+}
+
+struct CompositeTemplate {
+    builder: Arc<dyn Fn() -> CompositeFragment>,
+    in_types: Vec<AtomType>,
+    out_types: Vec<AtomType>,
+    weak_self: WeakSelf<Self>,
+}
+
+impl CompositeTemplate {
+    // `builder` is invoked once here (to learn the fragment's boundary
+    // types) and again on every `create()` call (to build an independent
+    // copy of the inner subgraph for each instantiation).
+    pub fn new(builder: Arc<dyn Fn() -> CompositeFragment>) -> Arc<Self> {
+        let prototype = builder();
+        let in_types = prototype
+            .in_boundary
+            .iter()
+            .map(|targets| {
+                let (node_idx, slot_idx) = targets[0];
+                prototype.nodes[node_idx].borrow().in_types()[slot_idx].into()
+            })
+            .collect();
+        let out_types = prototype
+            .out_boundary
+            .iter()
+            .map(|&(node_idx, slot_idx)| {
+                prototype.nodes[node_idx].borrow().out_links()[slot_idx]
+                    .borrow()
+                    .typ
+                    .into()
+            })
+            .collect();
+
+        let ret = Arc::new(Self {
+            builder,
+            in_types,
+            out_types,
+            weak_self: WeakSelf::new(),
+        });
+        ret.weak_self.init(&ret);
+        ret
+    }
+}
+
+impl NodeTemplate for CompositeTemplate {
+    fn in_types(&self) -> Vec<AtomType> {
+        self.in_types.clone()
+    }
+    fn out_types(&self) -> Vec<AtomType> {
+        self.out_types.clone()
+    }
+    fn create(&self, _subst: &Substitution) -> Arc<RefCell<dyn Node>> {
+        let fragment = (self.builder)();
+        let in_types = self
+            .in_types
+            .iter()
+            .map(|typ| resolve_concrete(*typ, &Substitution::new()))
+            .collect();
+        let in_links = fragment.in_boundary.iter().map(|_| None).collect();
+        let out_links = fragment
+            .out_boundary
+            .iter()
+            .map(|&(node_idx, slot_idx)| fragment.nodes[node_idx].borrow().out_links()[slot_idx].clone())
+            .collect();
+        Arc::new(RefCell::new(CompositeNode {
+            fragment,
+            in_types,
+            in_links,
+            out_links,
+            template: self.weak_self.get().upgrade().unwrap(),
+        }))
+    }
+}
+
 #[derive(Clone)]
 struct InputParameter {
     node: Weak<RefCell<dyn Node>>,
@@ -193,7 +575,6 @@ impl InputParameter {
 
 fn in_params(node: &Arc<RefCell<dyn Node>>) -> Vec<InputParameter> {
     node.borrow()
-        .template()
         .in_types()
         .iter()
         .enumerate()
@@ -214,24 +595,88 @@ struct OutputParameter {
 
 fn out_params(node: &Arc<RefCell<dyn Node>>) -> Vec<OutputParameter> {
     node.borrow()
-        .template()
-        .out_types()
+        .out_links()
         .iter()
         .enumerate()
-        .map(|(idx, typ)| OutputParameter {
+        .map(|(idx, link)| OutputParameter {
             node: Arc::downgrade(&node),
             idx,
-            typ: *typ,
+            typ: link.borrow().typ,
         })
         .collect()
 }
 
-fn attach(from_param: &OutputParameter, to_param: &InputParameter) {
-    if let Some(src_ref) = from_param.node.upgrade() {
-        let src = src_ref.borrow_mut();
+/* A coercion is a directed edge between two atom types, carrying the
+ * adapter `NodeTemplate` that actually performs the conversion (e.g. an
+ * `Entity -> TestUsize` node that extracts an id). `attach` walks these
+ * edges like a type checker walks an autoderef chain: when the source and
+ * sink types don't match outright, it looks for a path of adapters that
+ * bridges them instead of refusing to wire them together.
+ */
+struct CoercionEdge {
+    to: AtomDiscriminants,
+    adapter: Arc<dyn NodeTemplate>,
+}
+
+#[derive(Default)]
+struct CoercionRegistry {
+    edges: HashMap<AtomDiscriminants, Vec<CoercionEdge>>,
+}
+
+impl CoercionRegistry {
+    fn new() -> Self {
+        Default::default()
+    }
 
-        assert_eq!(from_param.typ, to_param.typ);
+    fn register(&mut self, from: AtomDiscriminants, to: AtomDiscriminants, adapter: Arc<dyn NodeTemplate>) {
+        self.edges
+            .entry(from)
+            .or_insert_with(Vec::new)
+            .push(CoercionEdge { to, adapter });
+    }
+
+    /* BFS over the coercion graph for the shortest chain of adapters that
+     * turns `from` into `to`. An empty chain means the types already match.
+     * Breadth-first search naturally prefers shorter chains, since it never
+     * visits a node through a longer path once a shorter one has claimed it.
+     */
+    fn path(&self, from: AtomDiscriminants, to: AtomDiscriminants) -> Option<Vec<Arc<dyn NodeTemplate>>> {
+        if from == to {
+            return Some(vec![]);
+        }
+
+        let mut visited = HashSet::new();
+        visited.insert(from);
+        let mut search_q = VecDeque::new();
+        search_q.push_back((from, Vec::<Arc<dyn NodeTemplate>>::new()));
+
+        while let Some((current, path)) = search_q.pop_front() {
+            for edge in self.edges.get(&current).into_iter().flatten() {
+                let mut next_path = path.clone();
+                next_path.push(edge.adapter.clone());
+                if edge.to == to {
+                    return Some(next_path);
+                }
+                if visited.insert(edge.to) {
+                    search_q.push_back((edge.to, next_path));
+                }
+            }
+        }
+        None
+    }
+
+    /* Number of adapter hops in the shortest coercion chain from `from` to
+     * `to`, used to prefer a nearer available type over a more distant one
+     * when several could satisfy the same requirement.
+     */
+    fn distance(&self, from: AtomDiscriminants, to: AtomDiscriminants) -> Option<usize> {
+        self.path(from, to).map(|adapters| adapters.len())
+    }
+}
 
+fn link_direct(from_param: &OutputParameter, to_param: &InputParameter) {
+    if let Some(src_ref) = from_param.node.upgrade() {
+        let src = src_ref.borrow_mut();
         src.out_links()[from_param.idx]
             .borrow_mut()
             .add_sink(&to_param);
@@ -239,6 +684,93 @@ fn attach(from_param: &OutputParameter, to_param: &InputParameter) {
     }
 }
 
+// Feeds `from_param` into the `source_idx`'th slot of `reduce_link`, which
+// must already be in reduce mode (see `Link::new_reduce`), instead of into a
+// plain `InputParameter` sink.
+fn link_into_reduce(from_param: &OutputParameter, reduce_link: &Arc<RefCell<Link>>, source_idx: usize) {
+    if let Some(src_ref) = from_param.node.upgrade() {
+        let src = src_ref.borrow_mut();
+        src.out_links()[from_param.idx]
+            .borrow_mut()
+            .add_reduce_sink(reduce_link, source_idx);
+    }
+}
+
+// Walks the shortest registered coercion chain from `from_param.typ` to
+// `to_typ`, splicing in an adapter node per hop, and returns the
+// `OutputParameter` that now actually carries `to_typ` (either `from_param`
+// itself, if the types already matched, or the last adapter's output)
+// alongside the adapter nodes created. Shared by `attach` and
+// `attach_combined`, which differ only in what they wire that final
+// `OutputParameter` into.
+fn splice_coercion(
+    from_param: &OutputParameter,
+    to_typ: AtomDiscriminants,
+    coercions: &CoercionRegistry,
+) -> (OutputParameter, Vec<Arc<RefCell<dyn Node>>>) {
+    if from_param.typ == to_typ {
+        return (from_param.clone(), vec![]);
+    }
+
+    let adapters = coercions.path(from_param.typ, to_typ).unwrap_or_else(|| {
+        panic!("no coercion path from {:?} to {:?}", from_param.typ, to_typ)
+    });
+
+    let mut current = from_param.clone();
+    let mut nodes = Vec::with_capacity(adapters.len());
+    for adapter in adapters {
+        let node = adapter.create(&Substitution::new());
+        link_direct(&current, &in_params(&node)[0]);
+        current = out_params(&node)[0].clone();
+        nodes.push(node);
+    }
+    (current, nodes)
+}
+
+// Returns the adapter nodes spliced in to bridge a coercion, if any; the
+// caller must hold onto these (alongside the rest of the graph's nodes) since
+// a `Node`'s wiring only holds `Weak` references to its neighbours.
+fn attach(
+    from_param: &OutputParameter,
+    to_param: &InputParameter,
+    coercions: &CoercionRegistry,
+) -> Vec<Arc<RefCell<dyn Node>>> {
+    let (source, nodes) = splice_coercion(from_param, to_param.typ, coercions);
+    link_direct(&source, to_param);
+    nodes
+}
+
+/* Attaches several producers onto a single sink whose value should be the
+ * monoidal fold of all of them (see `Combine`), instead of whichever
+ * producer's `Link` happens to fire last. A private reduce-mode `Link` (one
+ * slot per entry in `from_params`) is created and wired as the thing
+ * actually attached to `to_param`; each producer is spliced through its own
+ * coercion chain, same as a plain `attach`, into its own slot of that
+ * reduce `Link`. The reduce `Link` itself needs no separate bookkeeping to
+ * stay alive: each producer's own out-link holds a strong reference to it.
+ */
+fn attach_combined(
+    from_params: &[OutputParameter],
+    to_param: &InputParameter,
+    combiner: Arc<dyn Combine>,
+    coercions: &CoercionRegistry,
+) -> Vec<Arc<RefCell<dyn Node>>> {
+    let reduce_link = Arc::new(RefCell::new(Link::new_reduce(
+        to_param.typ,
+        from_params.len(),
+        combiner,
+    )));
+    reduce_link.borrow_mut().add_sink(to_param);
+
+    let mut nodes = Vec::new();
+    for (source_idx, from_param) in from_params.iter().enumerate() {
+        let (source, adapters) = splice_coercion(from_param, to_param.typ, coercions);
+        link_into_reduce(&source, &reduce_link, source_idx);
+        nodes.extend(adapters);
+    }
+    nodes
+}
+
 type TypeMultiset = HashMap<AtomDiscriminants, u8>;
 
 fn contains(haystack: &TypeMultiset, needle: &TypeMultiset) -> bool {
@@ -258,8 +790,135 @@ fn type_set(types: Vec<AtomDiscriminants>) -> TypeMultiset {
     counts
 }
 
-fn generate_graphs(templates: &Vec<Arc<dyn NodeTemplate>>) -> Vec<Vec<&Arc<dyn NodeTemplate>>> {
-    /* Returns the input nodes of a generated power graph. Generation occurs in two stages:
+// Renames a template's type variables to fresh, globally-unique ids (allocated
+// from `next_var`) so that two uses of the same polymorphic template in the
+// same search don't get their variables confused with one another. Variables
+// shared between `in_types` and `out_types` on the same template (e.g. the
+// `T` in an identity node's `T -> T`) keep referring to the same fresh id.
+fn freshen_one(typ: AtomType, mapping: &mut HashMap<u32, u32>, next_var: &mut u32) -> AtomType {
+    match typ {
+        AtomType::Concrete(_) => typ,
+        AtomType::Var(var) => {
+            let fresh = *mapping.entry(var).or_insert_with(|| {
+                let id = *next_var;
+                *next_var += 1;
+                id
+            });
+            AtomType::Var(fresh)
+        }
+    }
+}
+
+// The returned `mapping` is the template's own (pre-freshening) variable id
+// mapped to the fresh id it was renamed to, which callers need in order to
+// translate a `Substitution` produced against the fresh ids back into one
+// keyed on the template's original ids -- the only kind `NodeTemplate::create`
+// understands.
+fn freshen_template(
+    in_types: &[AtomType],
+    out_types: &[AtomType],
+    next_var: &mut u32,
+) -> (Vec<AtomType>, Vec<AtomType>, HashMap<u32, u32>) {
+    let mut mapping = HashMap::new();
+    let fresh_in = in_types
+        .iter()
+        .map(|typ| freshen_one(*typ, &mut mapping, next_var))
+        .collect();
+    let fresh_out = out_types
+        .iter()
+        .map(|typ| freshen_one(*typ, &mut mapping, next_var))
+        .collect();
+    (fresh_in, fresh_out, mapping)
+}
+
+// Tries to consume `in_types` out of `budget`, binding any type variable to
+// whichever available concrete type still has budget remaining. Backtracks
+// (via a cloned substitution) over the choice of binding when one candidate
+// doesn't let the rest of `in_types` be satisfied.
+fn match_in_types(
+    in_types: &[AtomType],
+    budget: &mut TypeMultiset,
+    subst: &mut Substitution,
+    coercions: &CoercionRegistry,
+) -> bool {
+    let (first, rest) = match in_types.split_first() {
+        None => return true,
+        Some(split) => split,
+    };
+    match resolve(*first, subst) {
+        AtomType::Concrete(discriminant) => {
+            // An available type satisfies the requirement outright, or via
+            // the shortest registered coercion path to it. Try nearest
+            // candidates (fewest adapter hops, an exact match being
+            // distance 0) first, but -- same as the `Var` case below --
+            // backtrack to the next-nearest if committing to a candidate
+            // forecloses the rest of `in_types`, rather than failing
+            // outright on the first (closest) candidate that doesn't pan
+            // out.
+            let mut candidates: Vec<(AtomDiscriminants, usize)> = budget
+                .iter()
+                .filter(|(_, &count)| count > 0)
+                .filter_map(|(&available, _)| {
+                    if available == discriminant {
+                        Some((available, 0))
+                    } else {
+                        coercions
+                            .distance(available, discriminant)
+                            .map(|dist| (available, dist))
+                    }
+                })
+                .collect();
+            candidates.sort_by_key(|&(_, dist)| dist);
+
+            for (available, _dist) in candidates {
+                let count = budget[&available];
+                budget.insert(available, count - 1);
+                if match_in_types(rest, budget, subst, coercions) {
+                    return true;
+                }
+                *budget.get_mut(&available).unwrap() += 1;
+            }
+            false
+        }
+        AtomType::Var(var) => {
+            let candidates: Vec<AtomDiscriminants> = budget
+                .iter()
+                .filter(|(_, &count)| count > 0)
+                .map(|(&discriminant, _)| discriminant)
+                .collect();
+            for discriminant in candidates {
+                let mut trial_subst = subst.clone();
+                if unify(AtomType::Var(var), discriminant.into(), &mut trial_subst).is_err() {
+                    continue;
+                }
+                let count = budget[&discriminant];
+                budget.insert(discriminant, count - 1);
+                if match_in_types(rest, budget, &mut trial_subst, coercions) {
+                    *subst = trial_subst;
+                    return true;
+                }
+                *budget.get_mut(&discriminant).unwrap() += 1;
+            }
+            false
+        }
+    }
+}
+
+// How many templates deep the search in `generate_graphs` is allowed to
+// chain before it gives up on a branch that still has a dangling output
+// nothing has consumed yet.
+const MAX_GENERATED_CHAIN_LEN: usize = 4;
+
+// A topsorted chain of templates, each paired with the `Substitution` (keyed
+// on that template's own variable ids) `generate_graphs` resolved it against.
+type GeneratedChain<'a> = Vec<(&'a Arc<dyn NodeTemplate>, Substitution)>;
+
+fn generate_graphs<'a>(
+    templates: &'a Vec<Arc<dyn NodeTemplate>>,
+    coercions: &CoercionRegistry,
+) -> Vec<GeneratedChain<'a>> {
+    /* Returns topsorted chains of templates for a generated power graph. Generation occurs in
+     * two stages:
      *
      * 1. Using type annotations, create a potential topsort of the graph's templates
      *  e.g. using types
@@ -267,97 +926,273 @@ fn generate_graphs(templates: &Vec<Arc<dyn NodeTemplate>>) -> Vec<Vec<&Arc<dyn N
      *      Id: A -> A
      *      Sink: A -> ()
      *
-     *  this phase could return [Source, Sink], [Source, Id, Sink], etc.
+     *  this phase could return [Source, Sink], [Source, Id, Sink], etc. A chain is only a
+     *  valid result once every output it has produced has also been consumed -- a chain with
+     *  a dangling, unconsumed output keeps being extended (up to `MAX_GENERATED_CHAIN_LEN`)
+     *  rather than being returned as-is.
+     *
+     * 2. Turn a topsorted template chain into an instantiated power graph, linking up nodes as
+     *    needed (see `attach`).
+     *
+     * Templates may be polymorphic (their `in_types`/`out_types` may contain
+     * `AtomType::Var`s), so before a template's inputs are checked against the
+     * available-type pool its variables are freshened and unified against
+     * that pool, producing a substitution that's then used to resolve its
+     * output types before they're added back to the pool. An available type
+     * that doesn't match a requirement outright can still satisfy it through
+     * a registered coercion, with shorter adapter chains preferred over
+     * longer ones.
      *
-     * 2. Turn that topsorted template into an instantiated power graph, linking up nodes as
-     *    needed.
+     * Each returned chain pairs every template with its own `Substitution`
+     * (keyed on that template's original, pre-freshening variable ids), so a
+     * caller can hand it straight to `NodeTemplate::create` without having to
+     * re-derive the binding the search already found.
      */
 
-    let templates_by_type: Vec<(TypeMultiset, &Arc<dyn NodeTemplate>, TypeMultiset)> = templates
-        .iter()
-        .map(|template| {
-            (
-                type_set(template.in_types()),
-                template,
-                type_set(template.out_types()),
-            )
-        })
-        .collect();
-    println!("templates_by_type: {:?}", templates_by_type);
-
-    let mut search_q: VecDeque<(TypeMultiset, Vec<&Arc<dyn NodeTemplate>>)> = VecDeque::new();
-    search_q.push_back((TypeMultiset::new(), Vec::new()));
+    let mut search_q: VecDeque<(TypeMultiset, GeneratedChain<'a>, u32)> = VecDeque::new();
+    search_q.push_back((TypeMultiset::new(), Vec::new(), 0));
 
     let mut results = Vec::new();
 
-    for i in 1..5 {
-        if let Some((available_types, prev_templates)) = search_q.pop_front() {
-            for (in_type_set, next_template, out_type_set) in templates_by_type
-                .iter()
-                .filter(|(type_set, _, _)| contains(&available_types, type_set))
-            {
-                let mut next_types = available_types.clone();
-                for (typ, count) in in_type_set {
-                    next_types.entry(*typ).and_modify(|e| *e -= count);
-                }
-                for (typ, count) in out_type_set {
-                    next_types
-                        .entry(*typ)
-                        .and_modify(|e| *e += count)
-                        .or_insert(*count);
-                }
-                let mut next_templates = prev_templates.clone();
-                next_templates.push(next_template);
+    while let Some((available_types, prev_templates, next_var)) = search_q.pop_front() {
+        if prev_templates.len() >= MAX_GENERATED_CHAIN_LEN {
+            continue;
+        }
+        for next_template in templates.iter() {
+            let mut next_var = next_var;
+            let (in_types, out_types, mapping) = freshen_template(
+                &next_template.in_types(),
+                &next_template.out_types(),
+                &mut next_var,
+            );
 
-                if next_types.iter().all(|(_k, count)| count == &0) {
-                    search_q.push_back((next_types, next_templates));
-                } else {
-                    results.push(next_templates);
+            let mut next_types = available_types.clone();
+            let mut subst = Substitution::new();
+            if !match_in_types(&in_types, &mut next_types, &mut subst, coercions) {
+                continue;
+            }
+
+            for out_type in &out_types {
+                // An output whose variable was never pinned down by an
+                // input (e.g. a pure source `() -> T`) can't be counted
+                // towards a concrete pool without a consumer to unify
+                // against, so it's left out of `next_types`.
+                if let AtomType::Concrete(discriminant) = resolve(*out_type, &subst) {
+                    *next_types.entry(discriminant).or_insert(0) += 1;
                 }
             }
+
+            // Translate the freshened-variable substitution back to the
+            // template's own variable ids via `mapping`, so the pair we
+            // record can be fed straight to `next_template.create(...)`.
+            let template_subst: Substitution = mapping
+                .into_iter()
+                .map(|(orig_var, fresh_var)| (orig_var, resolve(AtomType::Var(fresh_var), &subst)))
+                .collect();
+
+            let mut next_templates = prev_templates.clone();
+            next_templates.push((next_template, template_subst));
+
+            if next_types.iter().all(|(_k, count)| count == &0) {
+                results.push(next_templates);
+            } else {
+                search_q.push_back((next_types, next_templates, next_var));
+            }
         }
     }
     results
 }
 
-#[cfg(test)]
-#[derive(Default, Debug)]
-struct EmitUsizeState {}
-
-#[cfg(test)]
-impl NodeState for EmitUsizeState {
-    fn callback_fns(self: Arc<Self>) -> Vec<CallbackFn> {
-        vec![]
-    }
+/* A monoid DP over the `Node`/`Link` topology of an *instantiated*,
+ * tree-shaped graph (treating each `Link` as the edge between the node
+ * that owns it and each of its sinks). `f` folds the aggregate already
+ * accumulated on the far side of `incoming_edge` together with whatever
+ * `node` itself contributes; `operate`/`unit` are the monoid's combine and
+ * identity, exactly as in `Combine`, except here the accumulated value can
+ * be any `T` (e.g. a vector, for the motivating element-wise-max case)
+ * rather than only an `Atom`.
+ */
+trait RerootDp<T> {
+    fn f(&self, accumulated: &T, node: &Arc<RefCell<dyn Node>>, incoming_edge: &Arc<RefCell<Link>>) -> T;
+    fn operate(&self, a: &T, b: &T) -> T;
+    fn unit(&self) -> T;
 }
 
-#[cfg(test)]
-struct EmitUsizeTemplate {
-    weak_self: WeakSelf<Self>,
+// Finds `target`'s position in `nodes` by pointer identity, so edges
+// discovered by walking `Link::sinks` can be related back to an index.
+// `indices` maps each node's address to its position (built once by
+// `node_adjacency`), so each lookup is a hash probe rather than a scan.
+fn node_index(indices: &HashMap<*const (), usize>, target: &Arc<RefCell<dyn Node>>) -> Option<usize> {
+    indices.get(&(Arc::as_ptr(target) as *const ())).copied()
 }
 
-#[cfg(test)]
-impl EmitUsizeTemplate {
-    fn new() -> Arc<Self> {
-        let ret = Arc::new(Self {
-            weak_self: WeakSelf::new(),
-        });
-        ret.weak_self.init(&ret);
-        ret.into()
+// Builds an undirected adjacency list (indices into `nodes`, plus the
+// `Link` connecting each pair) by following every out-link's sinks back to
+// a node in `nodes`. A shared `Link` that fans out to several sinks
+// contributes one tree edge per sink, which is exactly what a tree-shaped
+// graph with a fan-out node looks like.
+//
+// A reduce-mode fan-in (`LinkSink::Reduce`, see `attach_combined`) isn't a
+// node-to-node tree edge at all -- several producers collapse through one
+// shared `Link` into a single folded value -- so rerooting over a graph
+// that contains one isn't supported; panic rather than silently treating
+// those producers as disconnected (which would make `reroot_aggregate`
+// return `unit()` everywhere without any indication why).
+fn node_adjacency(nodes: &[Arc<RefCell<dyn Node>>]) -> Vec<Vec<(usize, Arc<RefCell<Link>>)>> {
+    let indices: HashMap<*const (), usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (Arc::as_ptr(node) as *const (), i))
+        .collect();
+    let mut adjacency = vec![Vec::new(); nodes.len()];
+    for (i, node) in nodes.iter().enumerate() {
+        for link in node.borrow().out_links() {
+            for sink in &link.borrow().sinks {
+                match sink {
+                    LinkSink::Input(input) => {
+                        if let Some(sink_node) = input.node.upgrade() {
+                            if let Some(j) = node_index(&indices, &sink_node) {
+                                adjacency[i].push((j, link.clone()));
+                                adjacency[j].push((i, link.clone()));
+                            }
+                        }
+                    }
+                    LinkSink::Reduce(_, _) => panic!(
+                        "reroot_aggregate does not support reduce-mode (attach_combined) fan-in links"
+                    ),
+                }
+            }
+        }
     }
+    adjacency
 }
 
-#[cfg(test)]
-impl NodeTemplate for EmitUsizeTemplate {
-    fn in_types(&self) -> Vec<AtomDiscriminants> {
-        vec![]
-    }
-    fn out_types(&self) -> Vec<AtomDiscriminants> {
-        vec![AtomDiscriminants::TestUsize]
-    }
-    fn create(&self) -> Arc<RefCell<dyn Node>> {
-        SimpleNode::<EmitUsizeState>::from_template(self.weak_self.get().upgrade().unwrap())
-    }
+/* Rerooting: computes, for every node in a tree-shaped graph, the `RerootDp`
+ * aggregate over the *whole* graph as if that node were the root, in O(n)
+ * total rather than the O(n^2) of rerooting-by-brute-force.
+ *
+ * Pass 1 (post-order over an arbitrary rooting at index 0): `down[v]` folds
+ * `f(down[child], child, edge)` over `v`'s children.
+ *
+ * Pass 2 (pre-order): `up[v]` folds the aggregate of everything *outside*
+ * `v`'s subtree and pushes it down through `v`'s incoming edge. Since
+ * `operate` need not be invertible (an element-wise max can't be "divided
+ * out"), computing "all of `v`'s siblings except child `i`" uses a prefix
+ * and suffix fold over the ordered child list rather than subtracting `i`
+ * out of the full fold.
+ *
+ * The final aggregate at `v` is `operate(down[v], up[v])`.
+ */
+fn reroot_aggregate<T: Clone>(
+    nodes: &[Arc<RefCell<dyn Node>>],
+    dp: &dyn RerootDp<T>,
+) -> HashMap<usize, T> {
+    if nodes.is_empty() {
+        return HashMap::new();
+    }
+
+    let adjacency = node_adjacency(nodes);
+
+    // Root arbitrarily at node 0 and record each node's children (with the
+    // edge to each) via BFS; for a tree this also gives us, in `order`, a
+    // traversal whose reverse is a valid post-order.
+    let mut children: Vec<Vec<(usize, Arc<RefCell<Link>>)>> = vec![Vec::new(); nodes.len()];
+    let mut visited = vec![false; nodes.len()];
+    let mut order = Vec::with_capacity(nodes.len());
+    let mut queue = VecDeque::new();
+    queue.push_back(0usize);
+    visited[0] = true;
+    while let Some(u) = queue.pop_front() {
+        order.push(u);
+        for (v, link) in &adjacency[u] {
+            if !visited[*v] {
+                visited[*v] = true;
+                children[u].push((*v, link.clone()));
+                queue.push_back(*v);
+            }
+        }
+    }
+
+    let mut down: Vec<T> = vec![dp.unit(); nodes.len()];
+    for &u in order.iter().rev() {
+        let mut acc = dp.unit();
+        for (child, edge) in &children[u] {
+            let contribution = dp.f(&down[*child], &nodes[*child], edge);
+            acc = dp.operate(&acc, &contribution);
+        }
+        down[u] = acc;
+    }
+
+    let mut up: Vec<T> = vec![dp.unit(); nodes.len()];
+    for &u in &order {
+        let contributions: Vec<T> = children[u]
+            .iter()
+            .map(|(child, edge)| dp.f(&down[*child], &nodes[*child], edge))
+            .collect();
+
+        let n = contributions.len();
+        let mut prefix = vec![dp.unit(); n + 1];
+        for i in 0..n {
+            prefix[i + 1] = dp.operate(&prefix[i], &contributions[i]);
+        }
+        let mut suffix = vec![dp.unit(); n + 1];
+        for i in (0..n).rev() {
+            suffix[i] = dp.operate(&contributions[i], &suffix[i + 1]);
+        }
+
+        for (i, (child, edge)) in children[u].iter().enumerate() {
+            let siblings = dp.operate(&prefix[i], &suffix[i + 1]);
+            let outside_child = dp.operate(&up[u], &siblings);
+            up[*child] = dp.f(&outside_child, &nodes[u], edge);
+        }
+    }
+
+    (0..nodes.len())
+        .map(|i| (i, dp.operate(&down[i], &up[i])))
+        .collect()
+}
+
+#[cfg(test)]
+#[derive(Default, Debug)]
+struct EmitUsizeState {}
+
+#[cfg(test)]
+impl NodeState for EmitUsizeState {
+    fn callback_fns(self: Arc<Self>) -> Vec<CallbackFn> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+struct EmitUsizeTemplate {
+    weak_self: WeakSelf<Self>,
+}
+
+#[cfg(test)]
+impl EmitUsizeTemplate {
+    fn new() -> Arc<Self> {
+        let ret = Arc::new(Self {
+            weak_self: WeakSelf::new(),
+        });
+        ret.weak_self.init(&ret);
+        ret.into()
+    }
+}
+
+#[cfg(test)]
+impl NodeTemplate for EmitUsizeTemplate {
+    fn in_types(&self) -> Vec<AtomType> {
+        vec![]
+    }
+    fn out_types(&self) -> Vec<AtomType> {
+        vec![AtomDiscriminants::TestUsize.into()]
+    }
+    fn create(&self, subst: &Substitution) -> Arc<RefCell<dyn Node>> {
+        SimpleNode::<EmitUsizeState>::from_template(
+            self.weak_self.get().upgrade().unwrap(),
+            subst,
+        )
+    }
 }
 
 #[cfg(test)]
@@ -400,19 +1235,187 @@ impl TakeUsizeTemplate {
 
 #[cfg(test)]
 impl NodeTemplate for TakeUsizeTemplate {
-    fn in_types(&self) -> Vec<AtomDiscriminants> {
-        vec![AtomDiscriminants::TestUsize]
+    fn in_types(&self) -> Vec<AtomType> {
+        vec![AtomDiscriminants::TestUsize.into()]
     }
-    fn out_types(&self) -> Vec<AtomDiscriminants> {
+    fn out_types(&self) -> Vec<AtomType> {
         vec![]
     }
-    fn create(&self) -> Arc<RefCell<dyn Node>> {
+    fn create(&self, subst: &Substitution) -> Arc<RefCell<dyn Node>> {
         SimpleNode::<RefCell<TakeUsizeState>>::from_template(
             self.weak_self.get().upgrade().unwrap(),
+            subst,
         )
     }
 }
 
+#[cfg(test)]
+#[derive(Default, Debug)]
+struct EmitEntityState {}
+
+#[cfg(test)]
+impl NodeState for EmitEntityState {
+    fn callback_fns(self: Arc<Self>) -> Vec<CallbackFn> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+struct EmitEntityTemplate {
+    weak_self: WeakSelf<Self>,
+}
+
+#[cfg(test)]
+impl EmitEntityTemplate {
+    fn new() -> Arc<Self> {
+        let ret = Arc::new(Self {
+            weak_self: WeakSelf::new(),
+        });
+        ret.weak_self.init(&ret);
+        ret
+    }
+}
+
+#[cfg(test)]
+impl NodeTemplate for EmitEntityTemplate {
+    fn in_types(&self) -> Vec<AtomType> {
+        vec![]
+    }
+    fn out_types(&self) -> Vec<AtomType> {
+        vec![AtomDiscriminants::Entity.into()]
+    }
+    fn create(&self, subst: &Substitution) -> Arc<RefCell<dyn Node>> {
+        SimpleNode::<EmitEntityState>::from_template(self.weak_self.get().upgrade().unwrap(), subst)
+    }
+}
+
+// A coercion adapter used by tests: unwraps an `Entity`'s id as a `TestUsize`.
+#[cfg(test)]
+#[derive(Default, Debug)]
+struct EntityToUsizeState {}
+
+#[cfg(test)]
+impl NodeState for EntityToUsizeState {
+    fn callback_fns(self: Arc<Self>) -> Vec<CallbackFn> {
+        vec![Arc::new(|atom: Atom, links: &OutLinkList| {
+            if let Atom::Entity(id) = atom {
+                links[0].borrow_mut().update(0, Atom::TestUsize(id as usize));
+            } else {
+                panic!("wtf");
+            }
+        })]
+    }
+}
+
+#[cfg(test)]
+struct EntityToUsizeTemplate {
+    weak_self: WeakSelf<Self>,
+}
+
+#[cfg(test)]
+impl EntityToUsizeTemplate {
+    fn new() -> Arc<Self> {
+        let ret = Arc::new(Self {
+            weak_self: WeakSelf::new(),
+        });
+        ret.weak_self.init(&ret);
+        ret
+    }
+}
+
+#[cfg(test)]
+impl NodeTemplate for EntityToUsizeTemplate {
+    fn in_types(&self) -> Vec<AtomType> {
+        vec![AtomDiscriminants::Entity.into()]
+    }
+    fn out_types(&self) -> Vec<AtomType> {
+        vec![AtomDiscriminants::TestUsize.into()]
+    }
+    fn create(&self, subst: &Substitution) -> Arc<RefCell<dyn Node>> {
+        SimpleNode::<EntityToUsizeState>::from_template(
+            self.weak_self.get().upgrade().unwrap(),
+            subst,
+        )
+    }
+}
+
+// A pass-through `TestUsize -> TestUsize` node used to build multi-level
+// tree-shaped graphs in tests (e.g. for `reroot_aggregate`).
+#[cfg(test)]
+#[derive(Default, Debug)]
+struct RelayUsizeState {}
+
+#[cfg(test)]
+impl NodeState for RelayUsizeState {
+    fn callback_fns(self: Arc<Self>) -> Vec<CallbackFn> {
+        vec![Arc::new(|atom: Atom, links: &OutLinkList| {
+            links[0].borrow_mut().update(0, atom);
+        })]
+    }
+}
+
+#[cfg(test)]
+struct RelayUsizeTemplate {
+    weak_self: WeakSelf<Self>,
+}
+
+#[cfg(test)]
+impl RelayUsizeTemplate {
+    fn new() -> Arc<Self> {
+        let ret = Arc::new(Self {
+            weak_self: WeakSelf::new(),
+        });
+        ret.weak_self.init(&ret);
+        ret
+    }
+}
+
+#[cfg(test)]
+impl NodeTemplate for RelayUsizeTemplate {
+    fn in_types(&self) -> Vec<AtomType> {
+        vec![AtomDiscriminants::TestUsize.into()]
+    }
+    fn out_types(&self) -> Vec<AtomType> {
+        vec![AtomDiscriminants::TestUsize.into()]
+    }
+    fn create(&self, subst: &Substitution) -> Arc<RefCell<dyn Node>> {
+        SimpleNode::<RelayUsizeState>::from_template(self.weak_self.get().upgrade().unwrap(), subst)
+    }
+}
+
+// A polymorphic `T -> T` identity node, used to exercise `generate_graphs`'s
+// handling of `AtomType::Var`-typed templates end-to-end. `RelayUsizeState`'s
+// callback just forwards whatever `Atom` it receives, so it already behaves
+// correctly no matter what concrete type `T` ends up being resolved to.
+#[cfg(test)]
+struct IdentityTemplate {
+    weak_self: WeakSelf<Self>,
+}
+
+#[cfg(test)]
+impl IdentityTemplate {
+    fn new() -> Arc<Self> {
+        let ret = Arc::new(Self {
+            weak_self: WeakSelf::new(),
+        });
+        ret.weak_self.init(&ret);
+        ret
+    }
+}
+
+#[cfg(test)]
+impl NodeTemplate for IdentityTemplate {
+    fn in_types(&self) -> Vec<AtomType> {
+        vec![AtomType::Var(0)]
+    }
+    fn out_types(&self) -> Vec<AtomType> {
+        vec![AtomType::Var(0)]
+    }
+    fn create(&self, subst: &Substitution) -> Arc<RefCell<dyn Node>> {
+        SimpleNode::<RelayUsizeState>::from_template(self.weak_self.get().upgrade().unwrap(), subst)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::*;
@@ -421,14 +1424,14 @@ mod tests {
         let a_sig = EmitUsizeTemplate::new();
         let b_sig = TakeUsizeTemplate::new();
 
-        let a = a_sig.create();
-        let b = b_sig.create();
+        let a = a_sig.create(&Substitution::new());
+        let b = b_sig.create(&Substitution::new());
 
-        attach(&out_params(&a)[0], &in_params(&b)[0]);
+        attach(&out_params(&a)[0], &in_params(&b)[0], &CoercionRegistry::new());
 
         a.borrow().out_links()[0]
             .borrow_mut()
-            .update(Atom::TestUsize(5));
+            .update(0, Atom::TestUsize(5));
         assert_eq!(
             5,
             b.borrow()
@@ -440,13 +1443,153 @@ mod tests {
         );
     }
 
+    #[test]
+    fn attach_splices_coercion_adapter() {
+        let a_sig = EmitEntityTemplate::new();
+        let b_sig = TakeUsizeTemplate::new();
+        let adapter_sig = EntityToUsizeTemplate::new();
+
+        let a = a_sig.create(&Substitution::new());
+        let b = b_sig.create(&Substitution::new());
+
+        let mut coercions = CoercionRegistry::new();
+        coercions.register(AtomDiscriminants::Entity, AtomDiscriminants::TestUsize, adapter_sig);
+
+        let _adapters = attach(&out_params(&a)[0], &in_params(&b)[0], &coercions);
+
+        a.borrow().out_links()[0]
+            .borrow_mut()
+            .update(0, Atom::Entity(7));
+        assert_eq!(
+            7,
+            b.borrow()
+                .state()
+                .downcast_ref::<RefCell<TakeUsizeState>>()
+                .unwrap()
+                .borrow()
+                .received
+        );
+    }
+
+    #[test]
+    fn composite_fans_a_single_input_out_to_several_sinks() {
+        let take_a = TakeUsizeTemplate::new().create(&Substitution::new());
+        let take_b = TakeUsizeTemplate::new().create(&Substitution::new());
+
+        let inner = (take_a.clone(), take_b.clone());
+        let composite_template = CompositeTemplate::new(Arc::new(move || CompositeFragment {
+            nodes: vec![inner.0.clone(), inner.1.clone()],
+            in_boundary: vec![vec![(0, 0), (1, 0)]],
+            out_boundary: vec![],
+        }));
+
+        assert_eq!(
+            vec![AtomType::Concrete(AtomDiscriminants::TestUsize)],
+            composite_template.in_types()
+        );
+        assert_eq!(0, composite_template.out_types().len());
+
+        let composite = composite_template.create(&Substitution::new());
+        in_params(&composite)[0].mark_changed(Atom::TestUsize(9));
+
+        assert_eq!(
+            9,
+            take_a
+                .borrow()
+                .state()
+                .downcast_ref::<RefCell<TakeUsizeState>>()
+                .unwrap()
+                .borrow()
+                .received
+        );
+        assert_eq!(
+            9,
+            take_b
+                .borrow()
+                .state()
+                .downcast_ref::<RefCell<TakeUsizeState>>()
+                .unwrap()
+                .borrow()
+                .received
+        );
+    }
+
+    #[test]
+    fn empty_composite_is_a_pure_passthrough() {
+        let composite_template = CompositeTemplate::new(Arc::new(|| CompositeFragment {
+            nodes: vec![],
+            in_boundary: vec![],
+            out_boundary: vec![],
+        }));
+
+        assert_eq!(0, composite_template.in_types().len());
+        assert_eq!(0, composite_template.out_types().len());
+
+        let composite = composite_template.create(&Substitution::new());
+        assert_eq!(0, in_params(&composite).len());
+        assert_eq!(0, out_params(&composite).len());
+    }
+
     #[test]
     fn can_generate_graphs() {
         let templates: Vec<Arc<dyn NodeTemplate>> =
             vec![EmitUsizeTemplate::new(), TakeUsizeTemplate::new()];
 
-        let results = generate_graphs(&templates);
-        assert_eq!(1, results.len());
+        let results = generate_graphs(&templates, &CoercionRegistry::new());
+
+        // Every returned chain must actually be balanced: every output it
+        // produced was also consumed by something later in the chain.
+        assert!(!results.is_empty());
+        for chain in &results {
+            let mut types = TypeMultiset::new();
+            for (template, subst) in chain {
+                for out_type in template.out_types() {
+                    if let AtomType::Concrete(discriminant) = resolve(out_type, subst) {
+                        *types.entry(discriminant).or_insert(0) += 1;
+                    }
+                }
+                for in_type in template.in_types() {
+                    if let AtomType::Concrete(discriminant) = resolve(in_type, subst) {
+                        *types.get_mut(&discriminant).unwrap() -= 1;
+                    }
+                }
+            }
+            assert!(types.iter().all(|(_k, count)| count == &0));
+        }
+
+        // [Emit, Take] -- the shortest balanced chain -- must be among them.
+        assert!(results.iter().any(|chain| chain.len() == 2));
+    }
+
+    #[test]
+    fn generate_graphs_synthesizes_a_chain_through_a_polymorphic_identity_template() {
+        let templates: Vec<Arc<dyn NodeTemplate>> = vec![
+            EmitUsizeTemplate::new(),
+            IdentityTemplate::new(),
+            TakeUsizeTemplate::new(),
+        ];
+        let identity_template = &templates[1];
+
+        let results = generate_graphs(&templates, &CoercionRegistry::new());
+
+        let mut identity_subst = None;
+        'outer: for chain in &results {
+            for (template, subst) in chain {
+                if Arc::ptr_eq(*template, identity_template) {
+                    identity_subst = Some(subst);
+                    break 'outer;
+                }
+            }
+        }
+        let identity_subst =
+            identity_subst.expect("no synthesized chain used the polymorphic identity template");
+
+        // The identity template's `Var(0)` must have been pinned down to a
+        // concrete type by the search, so its own `Substitution` can
+        // actually instantiate it without hitting `resolve_concrete`'s
+        // "type variable ... left unresolved" panic.
+        let node = identity_template.create(identity_subst);
+        assert_eq!(&vec![AtomDiscriminants::TestUsize], node.borrow().in_types());
     }
 
     #[test]
@@ -465,4 +1608,355 @@ mod tests {
         assert_eq!(true, contains(&two, &one));
         assert_eq!(false, contains(&one, &two));
     }
+
+    #[test]
+    fn unification_binds_vars_and_respects_occurs_check() {
+        let mut subst = Substitution::new();
+
+        // Two concrete types unify only when they're the same discriminant.
+        assert!(unify(
+            AtomDiscriminants::Entity.into(),
+            AtomDiscriminants::Entity.into(),
+            &mut subst
+        )
+        .is_ok());
+        assert!(unify(
+            AtomDiscriminants::Entity.into(),
+            AtomDiscriminants::TestUsize.into(),
+            &mut Substitution::new()
+        )
+        .is_err());
+
+        // Binding a var makes it resolve to the concrete type it unified with.
+        assert!(unify(AtomType::Var(0), AtomDiscriminants::TestUsize.into(), &mut subst).is_ok());
+        assert_eq!(
+            AtomType::Concrete(AtomDiscriminants::TestUsize),
+            resolve(AtomType::Var(0), &subst)
+        );
+
+        // A var that's already bound must unify through its binding, so this
+        // fails because TestUsize != Entity.
+        assert!(unify(AtomType::Var(0), AtomDiscriminants::Entity.into(), &mut subst).is_err());
+
+        // Two unbound vars unify by pointing one at the other, and a var
+        // trivially unifies with itself without creating a cyclic binding.
+        let mut vars = Substitution::new();
+        assert!(unify(AtomType::Var(1), AtomType::Var(2), &mut vars).is_ok());
+        assert!(unify(AtomType::Var(1), AtomType::Var(1), &mut vars).is_ok());
+        assert!(occurs(2, AtomType::Var(1), &vars));
+    }
+
+    #[test]
+    fn builtin_combiners_fold_payloads() {
+        let entity = |v: u8| Atom::Entity(v);
+
+        assert_eq!(
+            10,
+            match SumCombine.operate(&entity(4), &entity(6)) {
+                Atom::Entity(v) => v,
+                _ => panic!("wtf"),
+            }
+        );
+        assert_eq!(
+            6,
+            match MaxCombine.operate(&entity(4), &entity(6)) {
+                Atom::Entity(v) => v,
+                _ => panic!("wtf"),
+            }
+        );
+        assert_eq!(
+            4,
+            match MinCombine.operate(&entity(4), &entity(6)) {
+                Atom::Entity(v) => v,
+                _ => panic!("wtf"),
+            }
+        );
+        assert_eq!(
+            0b110,
+            match OrCombine.operate(&entity(0b100), &entity(0b010)) {
+                Atom::Entity(v) => v,
+                _ => panic!("wtf"),
+            }
+        );
+
+        assert_eq!(0, match SumCombine.unit(AtomDiscriminants::Entity) {
+            Atom::Entity(v) => v,
+            _ => panic!("wtf"),
+        });
+        assert_eq!(u8::MAX, match MinCombine.unit(AtomDiscriminants::Entity) {
+            Atom::Entity(v) => v,
+            _ => panic!("wtf"),
+        });
+    }
+
+    #[test]
+    fn reduce_link_folds_multiple_sources_instead_of_clobbering() {
+        let link = Arc::new(RefCell::new(Link::new_reduce(
+            AtomDiscriminants::TestUsize,
+            3,
+            Arc::new(SumCombine),
+        )));
+        let sink = TakeUsizeTemplate::new().create(&Substitution::new());
+        link.borrow_mut().add_sink(&in_params(&sink)[0]);
+
+        link.borrow_mut().update(0, Atom::TestUsize(2));
+        link.borrow_mut().update(1, Atom::TestUsize(3));
+        link.borrow_mut().update(2, Atom::TestUsize(4));
+
+        let received = || {
+            sink.borrow()
+                .state()
+                .downcast_ref::<RefCell<TakeUsizeState>>()
+                .unwrap()
+                .borrow()
+                .received
+        };
+        assert_eq!(9, received());
+
+        // Re-updating one source's slot re-folds from `unit()`, rather than
+        // accumulating on top of the stale total.
+        link.borrow_mut().update(0, Atom::TestUsize(5));
+        assert_eq!(12, received());
+    }
+
+    #[test]
+    fn attach_combined_aggregates_several_producers_through_the_real_attach_api() {
+        let a = EmitUsizeTemplate::new().create(&Substitution::new());
+        let b = EmitUsizeTemplate::new().create(&Substitution::new());
+        let sink = TakeUsizeTemplate::new().create(&Substitution::new());
+
+        let _adapters = attach_combined(
+            &[out_params(&a)[0].clone(), out_params(&b)[0].clone()],
+            &in_params(&sink)[0],
+            Arc::new(SumCombine),
+            &CoercionRegistry::new(),
+        );
+
+        let received = || {
+            sink.borrow()
+                .state()
+                .downcast_ref::<RefCell<TakeUsizeState>>()
+                .unwrap()
+                .borrow()
+                .received
+        };
+
+        a.borrow().out_links()[0]
+            .borrow_mut()
+            .update(0, Atom::TestUsize(2));
+        assert_eq!(2, received());
+
+        b.borrow().out_links()[0]
+            .borrow_mut()
+            .update(0, Atom::TestUsize(3));
+        assert_eq!(5, received());
+    }
+
+    #[test]
+    #[should_panic(expected = "reduce-mode")]
+    fn reroot_aggregate_rejects_reduce_mode_fan_in() {
+        let a = EmitUsizeTemplate::new().create(&Substitution::new());
+        let b = EmitUsizeTemplate::new().create(&Substitution::new());
+        let sink = TakeUsizeTemplate::new().create(&Substitution::new());
+
+        let _adapters = attach_combined(
+            &[out_params(&a)[0].clone(), out_params(&b)[0].clone()],
+            &in_params(&sink)[0],
+            Arc::new(SumCombine),
+            &CoercionRegistry::new(),
+        );
+
+        struct NoopDp;
+        impl RerootDp<usize> for NoopDp {
+            fn f(&self, accumulated: &usize, _node: &Arc<RefCell<dyn Node>>, _edge: &Arc<RefCell<Link>>) -> usize {
+                *accumulated
+            }
+            fn operate(&self, a: &usize, b: &usize) -> usize {
+                a + b
+            }
+            fn unit(&self) -> usize {
+                0
+            }
+        }
+
+        reroot_aggregate(&[a, b, sink], &NoopDp);
+    }
+
+    #[test]
+    fn reroot_aggregate_counts_all_other_nodes_from_every_node() {
+        // a -> b -> c
+        //  \-> d
+        let a = EmitUsizeTemplate::new().create(&Substitution::new());
+        let b = RelayUsizeTemplate::new().create(&Substitution::new());
+        let c = TakeUsizeTemplate::new().create(&Substitution::new());
+        let d = TakeUsizeTemplate::new().create(&Substitution::new());
+
+        let coercions = CoercionRegistry::new();
+        attach(&out_params(&a)[0], &in_params(&b)[0], &coercions);
+        attach(&out_params(&b)[0], &in_params(&c)[0], &coercions);
+        attach(&out_params(&a)[0], &in_params(&d)[0], &coercions);
+
+        let nodes = vec![a, b, c, d];
+
+        struct CountOtherNodes;
+        impl RerootDp<usize> for CountOtherNodes {
+            fn f(
+                &self,
+                accumulated: &usize,
+                _node: &Arc<RefCell<dyn Node>>,
+                _incoming_edge: &Arc<RefCell<Link>>,
+            ) -> usize {
+                accumulated + 1
+            }
+            fn operate(&self, a: &usize, b: &usize) -> usize {
+                a + b
+            }
+            fn unit(&self) -> usize {
+                0
+            }
+        }
+
+        let totals = reroot_aggregate(&nodes, &CountOtherNodes);
+        for i in 0..nodes.len() {
+            // With any node as root, every other node is reachable exactly
+            // once, so the aggregate over the whole graph is `n - 1`.
+            assert_eq!(nodes.len() - 1, totals[&i]);
+        }
+    }
+
+    // Recomputes the same rerooted aggregate by literally re-rooting the
+    // adjacency at each node in turn and folding down from scratch (O(n)
+    // per root, O(n^2) total) -- i.e. without the prefix/suffix trick
+    // `reroot_aggregate` uses to avoid dividing a child's contribution back
+    // out of the full fold. Used as an oracle that's correct by
+    // construction even for a non-invertible `operate`.
+    fn brute_force_reroot<T: Clone>(
+        nodes: &[Arc<RefCell<dyn Node>>],
+        dp: &dyn RerootDp<T>,
+        adjacency: &[Vec<(usize, Arc<RefCell<Link>>)>],
+    ) -> HashMap<usize, T> {
+        let mut results = HashMap::new();
+        for root in 0..nodes.len() {
+            let mut visited = vec![false; nodes.len()];
+            let mut order = Vec::new();
+            let mut children: Vec<Vec<(usize, Arc<RefCell<Link>>)>> = vec![Vec::new(); nodes.len()];
+            let mut queue = VecDeque::new();
+            queue.push_back(root);
+            visited[root] = true;
+            while let Some(u) = queue.pop_front() {
+                order.push(u);
+                for (v, link) in &adjacency[u] {
+                    if !visited[*v] {
+                        visited[*v] = true;
+                        children[u].push((*v, link.clone()));
+                        queue.push_back(*v);
+                    }
+                }
+            }
+
+            let mut down = vec![dp.unit(); nodes.len()];
+            for &u in order.iter().rev() {
+                let mut acc = dp.unit();
+                for (child, edge) in &children[u] {
+                    let contribution = dp.f(&down[*child], &nodes[*child], edge);
+                    acc = dp.operate(&acc, &contribution);
+                }
+                down[u] = acc;
+            }
+            results.insert(root, down[root].clone());
+        }
+        results
+    }
+
+    #[test]
+    fn reroot_aggregate_matches_brute_force_for_noninvertible_elementwise_max() {
+        // A branching tree (more than one child per node), so the
+        // prefix/suffix "all siblings except me" logic actually gets
+        // exercised:
+        //
+        //      root
+        //     / | \
+        //    a  b  c
+        //   / \
+        //  d   e
+        let root = EmitUsizeTemplate::new().create(&Substitution::new());
+        let a = RelayUsizeTemplate::new().create(&Substitution::new());
+        let b = TakeUsizeTemplate::new().create(&Substitution::new());
+        let c = TakeUsizeTemplate::new().create(&Substitution::new());
+        let d = TakeUsizeTemplate::new().create(&Substitution::new());
+        let e = TakeUsizeTemplate::new().create(&Substitution::new());
+
+        let coercions = CoercionRegistry::new();
+        attach(&out_params(&root)[0], &in_params(&a)[0], &coercions);
+        attach(&out_params(&root)[0], &in_params(&b)[0], &coercions);
+        attach(&out_params(&root)[0], &in_params(&c)[0], &coercions);
+        attach(&out_params(&a)[0], &in_params(&d)[0], &coercions);
+        attach(&out_params(&a)[0], &in_params(&e)[0], &coercions);
+
+        let nodes = vec![root, a, b, c, d, e];
+        // One coordinate (index 0) peaks at node `b` (index 2); the other
+        // (index 1) peaks at node `e` (index 5). Neither is the arbitrary
+        // internal root (index 0). A naive "subtract the one child back
+        // out" rerooting only works for an invertible `operate` -- this
+        // uses element-wise max precisely because it isn't one.
+        let values: Vec<Vec<i64>> = vec![
+            vec![1, 0],
+            vec![0, 5],
+            vec![9, 0],
+            vec![0, 0],
+            vec![2, 2],
+            vec![0, 8],
+        ];
+
+        struct ElementwiseMaxDp<'a> {
+            nodes: &'a [Arc<RefCell<dyn Node>>],
+            values: &'a [Vec<i64>],
+        }
+
+        impl<'a> RerootDp<Vec<i64>> for ElementwiseMaxDp<'a> {
+            fn f(
+                &self,
+                accumulated: &Vec<i64>,
+                node: &Arc<RefCell<dyn Node>>,
+                _incoming_edge: &Arc<RefCell<Link>>,
+            ) -> Vec<i64> {
+                let idx = self
+                    .nodes
+                    .iter()
+                    .position(|candidate| Arc::ptr_eq(candidate, node))
+                    .unwrap();
+                self.operate(accumulated, &self.values[idx])
+            }
+            fn operate(&self, a: &Vec<i64>, b: &Vec<i64>) -> Vec<i64> {
+                a.iter().zip(b).map(|(x, y)| *x.max(y)).collect()
+            }
+            fn unit(&self) -> Vec<i64> {
+                vec![i64::MIN; 2]
+            }
+        }
+
+        let dp = ElementwiseMaxDp {
+            nodes: &nodes,
+            values: &values,
+        };
+        let adjacency = node_adjacency(&nodes);
+
+        let expected = brute_force_reroot(&nodes, &dp, &adjacency);
+        let actual = reroot_aggregate(&nodes, &dp);
+        for i in 0..nodes.len() {
+            assert_eq!(expected[&i], actual[&i], "mismatch at node {}", i);
+        }
+
+        // A node's own value is excluded from its own aggregate (it's only
+        // folded in when pulled across an edge towards some other node), so
+        // `b`'s peak on coordinate 0 should show up in everyone's aggregate
+        // except `b`'s own.
+        for i in 0..nodes.len() {
+            if i == 2 {
+                assert_ne!(9, actual[&i][0]);
+            } else {
+                assert_eq!(9, actual[&i][0]);
+            }
+        }
+    }
 }